@@ -0,0 +1,41 @@
+use nih_plug::prelude::Editor;
+use nih_plug_egui::{create_egui_editor, egui, widgets, EguiState};
+use std::sync::Arc;
+
+use crate::DelayParams;
+
+pub(crate) fn default_state() -> Arc<EguiState> {
+    EguiState::from_size(300, 260)
+}
+
+pub(crate) fn create(
+    params: Arc<DelayParams>,
+    editor_state: Arc<EguiState>,
+) -> Option<Box<dyn Editor>> {
+    create_egui_editor(
+        editor_state,
+        (),
+        |_, _| {},
+        move |egui_ctx, setter, _state| {
+            egui::CentralPanel::default().show(egui_ctx, |ui| {
+                ui.label("Delay");
+
+                ui.add(widgets::ParamSlider::for_param(&params.time, setter));
+                ui.add(widgets::ParamSlider::for_param(&params.feedback, setter));
+                ui.add(widgets::ParamSlider::for_param(&params.mix, setter));
+                ui.add(widgets::ParamSlider::for_param(&params.tone, setter));
+                ui.add(widgets::ParamSlider::for_param(&params.duck, setter));
+                ui.add(widgets::ParamSlider::for_param(&params.freeze, setter));
+
+                ui.separator();
+
+                ui.add(widgets::ParamSlider::for_param(&params.sync, setter));
+                ui.add(widgets::ParamSlider::for_param(
+                    &params.note_division,
+                    setter,
+                ));
+                ui.add(widgets::ParamSlider::for_param(&params.routing, setter));
+            });
+        },
+    )
+}
@@ -1,9 +1,132 @@
 use nih_plug::prelude::*;
+use nih_plug_egui::EguiState;
 use std::sync::Arc;
 
+mod editor;
+
+/// How quickly `current_delay_samples` chases the target delay length (from the `time` param, or
+/// from the host tempo and note division when `sync` is on).
+/// Smaller is slower/smoother. This turns `time` automation into a tape/BBD-style glide instead
+/// of a hard jump between whole-sample read taps.
+const DELAY_SLEW_COEFF: f32 = 0.002;
+
+/// Attack/release coefficients for the sidechain envelope follower used to drive ducking.
+/// Attack is fast so the duck grabs onto transients quickly; release is slow so it doesn't pump.
+const DUCK_ATTACK_COEFF: f32 = 0.2;
+const DUCK_RELEASE_COEFF: f32 = 0.01;
+
+/// The one-pole low-pass coefficient `a` (`lp += a * (x - lp)`) for a given cutoff frequency,
+/// derived so the cutoff stays correct across sample rates.
+fn one_pole_coefficient(cutoff_hz: f32, sample_rate: f32) -> f32 {
+    1.0 - (-2.0 * std::f32::consts::PI * cutoff_hz / sample_rate).exp()
+}
+
+/// Reads the delay line `i + frac` samples back, linearly interpolating between the two samples
+/// surrounding the fractional tap. Reads past the end of the line come back as silence.
+fn read_interpolated(deque: &std::collections::VecDeque<f32>, i: usize, frac: f32) -> f32 {
+    let x0 = *deque.get(i).unwrap_or(&0.0);
+    let x1 = *deque.get(i + 1).unwrap_or(&0.0);
+    x0 * (1.0 - frac) + x1 * frac
+}
+
+/// One step of an attack/release envelope follower chasing `level`.
+fn envelope_step(current: f32, level: f32, attack: f32, release: f32) -> f32 {
+    let coeff = if level > current { attack } else { release };
+    current + coeff * (level - current)
+}
+
+/// MIDI note that engages freeze for as long as it's held, in addition to the `freeze` param.
+const FREEZE_NOTE: u8 = 60;
+
+/// How quickly the write gain crossfades in/out of freeze, so toggling it doesn't click.
+const FREEZE_CROSSFADE_COEFF: f32 = 0.005;
+
+/// Builds a [`NonZeroU32`] from a non-zero literal at compile time, for use in the aux bus
+/// layout below where a plain `u32` isn't accepted.
+const fn nz(n: u32) -> NonZeroU32 {
+    match NonZeroU32::new(n) {
+        Some(n) => n,
+        None => panic!("nz() called with 0"),
+    }
+}
+
+/// A note division used to compute a tempo-synced delay length, relative to a quarter note.
+#[derive(Enum, Debug, PartialEq, Eq)]
+enum NoteDivision {
+    #[name = "1/4"]
+    Quarter,
+    #[name = "1/4t"]
+    QuarterTriplet,
+    #[name = "1/4."]
+    QuarterDotted,
+    #[name = "1/8"]
+    Eighth,
+    #[name = "1/8t"]
+    EighthTriplet,
+    #[name = "1/8."]
+    EighthDotted,
+    #[name = "1/16"]
+    Sixteenth,
+    #[name = "1/16t"]
+    SixteenthTriplet,
+    #[name = "1/16."]
+    SixteenthDotted,
+}
+
+impl NoteDivision {
+    /// The division's length in quarter notes, e.g. a triplet is 2/3 and a dotted note is 3/2 of
+    /// the plain division.
+    fn quarter_notes(&self) -> f32 {
+        match self {
+            NoteDivision::Quarter => 1.0,
+            NoteDivision::QuarterTriplet => 1.0 * 2.0 / 3.0,
+            NoteDivision::QuarterDotted => 1.0 * 3.0 / 2.0,
+            NoteDivision::Eighth => 0.5,
+            NoteDivision::EighthTriplet => 0.5 * 2.0 / 3.0,
+            NoteDivision::EighthDotted => 0.5 * 3.0 / 2.0,
+            NoteDivision::Sixteenth => 0.25,
+            NoteDivision::SixteenthTriplet => 0.25 * 2.0 / 3.0,
+            NoteDivision::SixteenthDotted => 0.25 * 3.0 / 2.0,
+        }
+    }
+}
+
+/// How the two delay lines feed back into each other.
+#[derive(Enum, Debug, PartialEq, Eq)]
+enum Routing {
+    /// Each channel delays itself.
+    Stereo,
+    /// Each channel's feedback is taken from the *other* channel's delayed output, so repeats
+    /// bounce between speakers.
+    PingPong,
+}
+
+/// Picks which delayed sample feeds back into which channel's delay line for a given [`Routing`].
+fn route_feedback(routing: Routing, lp_l: f32, lp_r: f32) -> (f32, f32) {
+    match routing {
+        Routing::Stereo => (lp_l, lp_r),
+        Routing::PingPong => (lp_r, lp_l),
+    }
+}
+
 struct Delay {
     params: Arc<DelayParams>,
-    deque: std::collections::VecDeque<f32>,
+    deque_l: std::collections::VecDeque<f32>,
+    deque_r: std::collections::VecDeque<f32>,
+    /// The delay length actually in use, in samples. Slews toward the target length (from `time`,
+    /// or from the host tempo when `sync` is on) every sample rather than snapping to it, and is
+    /// read with linear interpolation so the tap can land between samples.
+    current_delay_samples: f32,
+    /// One-pole low-pass filter state for the left/right feedback paths, used to darken repeats.
+    lp_l: f32,
+    lp_r: f32,
+    /// Envelope follower level (0-1-ish, can exceed 1 on loud peaks) driving sidechain ducking.
+    duck_env: f32,
+    /// Whether [`FREEZE_NOTE`] is currently held down.
+    midi_note_frozen: bool,
+    /// Crossfaded gain applied to the input before it's written into the delay lines: 1.0
+    /// normally, slewed to 0.0 while frozen so held content loops without new input bleeding in.
+    write_gain: f32,
 }
 
 /// The [`Params`] derive macro gathers all of the information needed for the wrapper to know about
@@ -20,13 +143,42 @@ struct DelayParams {
 
     #[id = "mix"]
     pub mix: FloatParam,
+
+    #[id = "sync"]
+    pub sync: BoolParam,
+
+    #[id = "note_division"]
+    pub note_division: EnumParam<NoteDivision>,
+
+    #[id = "routing"]
+    pub routing: EnumParam<Routing>,
+
+    #[id = "tone"]
+    pub tone: FloatParam,
+
+    #[id = "duck"]
+    pub duck: FloatParam,
+
+    #[id = "freeze"]
+    pub freeze: BoolParam,
+
+    /// The editor's size, persisted so the window reopens at the size the user left it at.
+    #[persist = "editor-state"]
+    editor_state: Arc<EguiState>,
 }
 
 impl Default for Delay {
     fn default() -> Self {
         Self {
             params: Arc::new(DelayParams::default()),
-            deque: std::collections::VecDeque::new(),
+            deque_l: std::collections::VecDeque::new(),
+            deque_r: std::collections::VecDeque::new(),
+            current_delay_samples: 0.0,
+            lp_l: 0.0,
+            lp_r: 0.0,
+            duck_env: 0.0,
+            midi_note_frozen: false,
+            write_gain: 1.0,
         }
     }
 }
@@ -50,6 +202,34 @@ impl Default for DelayParams {
 
             mix: FloatParam::new("Mix", 0.5, FloatRange::Linear { min: 0.0, max: 1.0 })
                 .with_value_to_string(formatters::v2s_f32_rounded(2)),
+
+            sync: BoolParam::new("Sync", false),
+
+            note_division: EnumParam::new("Division", NoteDivision::Eighth),
+
+            routing: EnumParam::new("Routing", Routing::Stereo),
+
+            // Expressed directly as the feedback low-pass's cutoff frequency, so the knob always
+            // audibly darkens repeats as it's turned down instead of having a dead zone at one
+            // end and no damping at the other.
+            tone: FloatParam::new(
+                "Tone",
+                4_000.0,
+                FloatRange::Skewed {
+                    min: 200.0,
+                    max: 20_000.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(0))
+            .with_unit(" Hz"),
+
+            duck: FloatParam::new("Duck", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_value_to_string(formatters::v2s_f32_rounded(2)),
+
+            freeze: BoolParam::new("Freeze", false),
+
+            editor_state: editor::default_state(),
         }
     }
 }
@@ -69,10 +249,18 @@ impl Plugin for Delay {
     const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[AudioIOLayout {
         main_input_channels: NonZeroU32::new(2),
         main_output_channels: NonZeroU32::new(2),
+        // A stereo sidechain input, used to duck the wet signal under whatever's plugged into it
+        // (typically a vocal).
+        aux_input_ports: &[nz(2)],
+        names: PortNames {
+            aux_inputs: &["Sidechain"],
+            ..PortNames::const_default()
+        },
         ..AudioIOLayout::const_default()
     }];
 
-    const MIDI_INPUT: MidiConfig = MidiConfig::None;
+    // `Basic` is enough to read the note on/off events `FREEZE_NOTE` is looked up from.
+    const MIDI_INPUT: MidiConfig = MidiConfig::Basic;
     // Setting this to `true` will tell the wrapper to split the buffer up into smaller blocks
     // whenever there are inter-buffer parameter changes. This way no changes to the plugin are
     // required to support sample accurate automation and the wrapper handles all of the boring
@@ -93,6 +281,10 @@ impl Plugin for Delay {
         self.params.clone()
     }
 
+    fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
+        editor::create(self.params.clone(), self.params.editor_state.clone())
+    }
+
     // This plugin doesn't need any special initialization, but if you need to do anything expensive
     // then this would be the place. State is kept around when the host reconfigures the
     // plugin. If we do need special initialization, we could implement the `initialize()` and/or
@@ -104,39 +296,166 @@ impl Plugin for Delay {
         buffer_config: &BufferConfig,
         _context: &mut impl InitContext<Self>,
     ) -> bool {
-        self.deque =
-            std::collections::VecDeque::with_capacity((2.0 * buffer_config.sample_rate) as usize);
+        // Pre-fill the delay lines to a fixed length matching the maximum delay time so
+        // `process` can pop the back and push the front every sample without ever reallocating
+        // or needing to index-check against the capacity.
+        let capacity = (2.0 * buffer_config.sample_rate) as usize;
+        self.deque_l = std::collections::VecDeque::from(vec![0.0; capacity]);
+        self.deque_r = std::collections::VecDeque::from(vec![0.0; capacity]);
         true
     }
 
     fn process(
         &mut self,
         buffer: &mut Buffer,
-        _aux: &mut AuxiliaryBuffers,
+        aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
         let time = self.params.time.value();
         let feedback = self.params.feedback.value();
         let mix = self.params.mix.value();
+        let duck = self.params.duck.value();
+
+        // The sidechain bus declared in `AUDIO_IO_LAYOUTS` is always present; hosts feed it
+        // silence when nothing is patched in, so `duck` only does something audible once
+        // something is actually routed to the sidechain input.
+        let mut sidechain_samples = aux.inputs[0].iter_samples();
+
+        let sample_rate = context.transport().sample_rate;
+
+        // `tone`'s mapping to a coefficient involves a transcendental `exp()`; the param is
+        // constant for the whole block under sample-accurate automation, so compute it once here
+        // instead of once per sample.
+        let tone_coeff = one_pole_coefficient(self.params.tone.value(), sample_rate);
 
-        // Calculate the index of the sample before time interval specified
+        // When sync is on, derive the delay length from the host tempo and the selected note
+        // division instead of the free-running `time` slider. Hosts that don't report a tempo
+        // (e.g. no transport, or a standalone run) fall back to `time`.
+        let synced_seconds = if self.params.sync.value() {
+            context.transport().tempo.map(|tempo| {
+                (60.0 / tempo as f32) * self.params.note_division.value().quarter_notes()
+            })
+        } else {
+            None
+        };
+
+        // Calculate the target delay length in (fractional) samples.
         // -1.0 is there because the most recent sample in the queue has index 0
-        let index = (-1.0 + time * 0.001 * context.transport().sample_rate) as usize;
-
-        for mut channel_samples in buffer.iter_samples() {
-            let delay = *self.deque.get(index).unwrap_or(&0.0);
-            // Remove the last sample in the back of the queue to make room for next push_front()
-            self.deque
-                .remove((-1.0 + 2.0 * context.transport().sample_rate) as usize);
-            self.deque
-                .push_front(*channel_samples.get_mut(0).unwrap() + delay * feedback);
-            *channel_samples.get_mut(0).unwrap() =
-                *channel_samples.get_mut(0).unwrap() * (1.0 - mix) + delay * mix;
-            *channel_samples.get_mut(1).unwrap() = *channel_samples.get_mut(0).unwrap();
+        let target_delay_samples = match synced_seconds {
+            Some(seconds) => -1.0 + seconds * sample_rate,
+            None => -1.0 + time * 0.001 * sample_rate,
+        };
+        // A slow tempo and a long note division can ask for a delay longer than the ring buffer
+        // holds (e.g. a dotted quarter below ~40 BPM). Clamp to what the buffer can actually
+        // supply instead of silently reading past the end and getting `0.0` back.
+        let max_delay_samples = self.deque_l.len() as f32 - 2.0;
+        let target_delay_samples = target_delay_samples.min(max_delay_samples);
+
+        let mut next_event = context.next_event();
+        for (sample_id, mut channel_samples) in buffer.iter_samples().enumerate() {
+            // Handle note on/off for `FREEZE_NOTE` sample-accurately so the freeze toggle lines
+            // up with the event rather than the start of the block.
+            while let Some(event) = next_event {
+                if event.timing() > sample_id as u32 {
+                    break;
+                }
+
+                match event {
+                    NoteEvent::NoteOn { note, .. } if note == FREEZE_NOTE => {
+                        self.midi_note_frozen = true
+                    }
+                    NoteEvent::NoteOff { note, .. } if note == FREEZE_NOTE => {
+                        self.midi_note_frozen = false
+                    }
+                    _ => (),
+                }
+
+                next_event = context.next_event();
+            }
+
+            let frozen = self.params.freeze.value() || self.midi_note_frozen;
+            // Crossfade the write gain in/out of freeze instead of toggling it instantly, so
+            // entering/exiting freeze doesn't click.
+            self.write_gain += FREEZE_CROSSFADE_COEFF * (if frozen { 0.0 } else { 1.0 } - self.write_gain);
+            // Force the feedback to unity while frozen so the held content loops indefinitely
+            // instead of decaying.
+            let feedback = if frozen { 1.0 } else { feedback };
+
+            // Slew toward the target instead of snapping so that moving `time` doesn't make the
+            // read tap jump between whole samples.
+            self.current_delay_samples +=
+                DELAY_SLEW_COEFF * (target_delay_samples - self.current_delay_samples);
+
+            // Linearly interpolate between the two samples surrounding the fractional tap.
+            let delay_samples = self.current_delay_samples.max(0.0);
+            let i = delay_samples.floor() as usize;
+            let frac = delay_samples - i as f32;
+            let delay_l = read_interpolated(&self.deque_l, i, frac);
+            let delay_r = read_interpolated(&self.deque_r, i, frac);
+
+            // Darken what gets fed back (but not what's heard in the wet signal) with a one-pole
+            // low-pass, so repeats lose top end the way a tape/analog delay would instead of
+            // staying sterile at high feedback.
+            self.lp_l += tone_coeff * (delay_l - self.lp_l);
+            self.lp_r += tone_coeff * (delay_r - self.lp_r);
+
+            // In ping-pong mode each line's feedback comes from the *other* line's delayed
+            // output, so repeats bounce between channels instead of each channel delaying itself.
+            // While frozen, recirculate the raw (unfiltered) delayed signal instead of the
+            // low-passed one: a one-pole low-pass only has unity gain at DC, so looping the
+            // filtered signal at "unity" feedback would still decay every lap instead of holding.
+            let (feedback_l, feedback_r) = if frozen {
+                route_feedback(self.params.routing.value(), delay_l, delay_r)
+            } else {
+                route_feedback(self.params.routing.value(), self.lp_l, self.lp_r)
+            };
+
+            // Pop the oldest sample off the back before pushing the new one onto the front,
+            // keeping each ring buffer at its fixed length from `initialize`.
+            self.deque_l.pop_back();
+            self.deque_r.pop_back();
+
+            let input_l = *channel_samples.get_mut(0).unwrap();
+            let input_r = *channel_samples.get_mut(1).unwrap();
+            self.deque_l
+                .push_front(input_l * self.write_gain + feedback_l * feedback);
+            self.deque_r
+                .push_front(input_r * self.write_gain + feedback_r * feedback);
+
+            // Envelope-follow the sidechain and use the level to duck the wet signal, so the
+            // delay tail drops out of the way of whatever's routed into the sidechain input.
+            let mut sc_samples = sidechain_samples.next().unwrap();
+            let sidechain_level = {
+                let n = sc_samples.len().max(1) as f32;
+                sc_samples.iter_mut().map(|s| s.abs()).sum::<f32>() / n
+            };
+            self.duck_env = envelope_step(
+                self.duck_env,
+                sidechain_level,
+                DUCK_ATTACK_COEFF,
+                DUCK_RELEASE_COEFF,
+            );
+            let duck_gain = 1.0 - duck * self.duck_env.min(1.0);
+
+            *channel_samples.get_mut(0).unwrap() = input_l * (1.0 - mix) + delay_l * duck_gain * mix;
+            *channel_samples.get_mut(1).unwrap() = input_r * (1.0 - mix) + delay_r * duck_gain * mix;
         }
         ProcessStatus::Normal
     }
 
+    fn reset(&mut self) {
+        // Zero the delay lines in place rather than reallocating, so a fresh ring buffer is
+        // ready before the host starts feeding audio again and no stale echo leaks out.
+        self.deque_l.iter_mut().for_each(|sample| *sample = 0.0);
+        self.deque_r.iter_mut().for_each(|sample| *sample = 0.0);
+        self.current_delay_samples = 0.0;
+        self.lp_l = 0.0;
+        self.lp_r = 0.0;
+        self.duck_env = 0.0;
+        self.midi_note_frozen = false;
+        self.write_gain = 1.0;
+    }
+
     // This can be used for cleaning up special resources like socket connections whenever the
     // plugin is deactivated. Most plugins won't need to do anything here.
     fn deactivate(&mut self) {}
@@ -158,3 +477,82 @@ impl Vst3Plugin for Delay {
 
 nih_export_clap!(Delay);
 nih_export_vst3!(Delay);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn note_division_quarter_notes() {
+        assert_eq!(NoteDivision::Quarter.quarter_notes(), 1.0);
+        assert_eq!(NoteDivision::Eighth.quarter_notes(), 0.5);
+        assert_eq!(NoteDivision::Sixteenth.quarter_notes(), 0.25);
+        assert!((NoteDivision::QuarterTriplet.quarter_notes() - 2.0 / 3.0).abs() < 1e-6);
+        assert!((NoteDivision::EighthDotted.quarter_notes() - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn read_interpolated_blends_neighbouring_samples() {
+        let deque = VecDeque::from(vec![1.0, 0.0, 0.0]);
+        assert_eq!(read_interpolated(&deque, 0, 0.0), 1.0);
+        assert_eq!(read_interpolated(&deque, 0, 0.5), 0.5);
+        assert_eq!(read_interpolated(&deque, 0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn read_interpolated_past_the_end_reads_as_silence() {
+        let deque: VecDeque<f32> = VecDeque::new();
+        assert_eq!(read_interpolated(&deque, 0, 0.5), 0.0);
+    }
+
+    #[test]
+    fn route_feedback_stereo_keeps_channels_independent() {
+        assert_eq!(route_feedback(Routing::Stereo, 1.0, 2.0), (1.0, 2.0));
+    }
+
+    #[test]
+    fn route_feedback_ping_pong_swaps_channels() {
+        assert_eq!(route_feedback(Routing::PingPong, 1.0, 2.0), (2.0, 1.0));
+    }
+
+    #[test]
+    fn one_pole_coefficient_lower_cutoff_damps_harder() {
+        let sample_rate = 48_000.0;
+        let dark = one_pole_coefficient(200.0, sample_rate);
+        let bright = one_pole_coefficient(8_000.0, sample_rate);
+        assert!(dark > 0.0 && dark < bright && bright < 1.0);
+    }
+
+    #[test]
+    fn envelope_step_attacks_up_and_releases_down() {
+        assert_eq!(envelope_step(0.0, 1.0, 0.5, 0.1), 0.5);
+        assert_eq!(envelope_step(1.0, 0.0, 0.5, 0.1), 0.9);
+    }
+
+    #[test]
+    fn reset_clears_delay_and_filter_state() {
+        let mut delay = Delay {
+            deque_l: VecDeque::from(vec![1.0; 4]),
+            deque_r: VecDeque::from(vec![1.0; 4]),
+            current_delay_samples: 5.0,
+            lp_l: 1.0,
+            lp_r: 1.0,
+            duck_env: 1.0,
+            midi_note_frozen: true,
+            write_gain: 0.0,
+            ..Delay::default()
+        };
+
+        delay.reset();
+
+        assert!(delay.deque_l.iter().all(|&s| s == 0.0));
+        assert!(delay.deque_r.iter().all(|&s| s == 0.0));
+        assert_eq!(delay.current_delay_samples, 0.0);
+        assert_eq!(delay.lp_l, 0.0);
+        assert_eq!(delay.lp_r, 0.0);
+        assert_eq!(delay.duck_env, 0.0);
+        assert!(!delay.midi_note_frozen);
+        assert_eq!(delay.write_gain, 1.0);
+    }
+}